@@ -1,376 +1,999 @@
-/*!
-# youlog
-
-A thin logging implementation for Rust's [log](https://github.com/rust-lang/log) facade.
-
-This crate allows for providing custom functions to the logger.
-
-Examples where this might be useful:
-
-- Logging logic needs to be different across log levels
-- Another application's logger is being used like with [godot-rust](https://github.com/godot-rust)
-- An existing crate is too opinionated in how it handles logging
-
-## Features
-
-- Setting logging functions per log level
-- Setting a logging function across all log levels
-- Filtering logs per module/filter
-- Initializing filters from an environment variable (`RUST_LOG` by default)
-
-## Example
-
-```
-use log::LevelFilter;
-use youlog::Youlog;
-
-Youlog::new()
-    .global_level(LevelFilter::Info)
-    .log_fn(LevelFilter::Info, |record| {
-        println!("info {}", record.args().as_str().unwrap_or_default());
-    })
-    .raw_fn(|record| {
-        println!("raw {}", record.args().as_str().unwrap_or_default());
-    })
-    .level("some_module", LevelFilter::Error)
-    .init()
-    .expect("unable to init logger");
-
-log::info!("this is an info log!");
-```
-
-# License
-
-MPL-2.0
-
-Filter implementation referenced from [`env_logger`](https://github.com/rust-cli/env_logger).
-*/
-
-use log::LevelFilter;
-use std::ffi::OsStr;
-
-type LogFn = Box<dyn Fn(&log::Record) + Sync + Send>;
-
-/// The default environment variable containing logging filters.
-pub const DEFAULT_ENV: &str = "RUST_LOG";
-
-/// A filter for a module.
-#[derive(Clone)]
-struct Filter {
-    /// The name of the module to filter.
-    name: String,
-    /// The max logging level for the module.
-    level: LevelFilter,
-}
-
-/// A logger that accepts user functions. Filters are optional, and by default all logs
-/// are enabled. This allows the user functions to implement their own per-level filters.
-pub struct Youlog {
-    max_level: LevelFilter,
-    filters: Vec<Filter>,
-
-    raw_fn: LogFn,
-    info_fn: LogFn,
-    warn_fn: LogFn,
-    error_fn: LogFn,
-    debug_fn: LogFn,
-    trace_fn: LogFn,
-}
-
-impl log::Log for Youlog {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        for filter in self.filters.iter().rev() {
-            if !metadata.target().starts_with(&filter.name) {
-                continue;
-            }
-
-            return metadata.level() <= filter.level;
-        }
-
-        false
-    }
-
-    fn log(&self, record: &log::Record) {
-        (self.raw_fn)(record);
-        match record.level() {
-            log::Level::Error => (self.error_fn)(record),
-            log::Level::Warn => (self.warn_fn)(record),
-            log::Level::Info => (self.info_fn)(record),
-            log::Level::Debug => (self.debug_fn)(record),
-            log::Level::Trace => (self.trace_fn)(record),
-        }
-    }
-
-    fn flush(&self) {}
-}
-
-impl Youlog {
-    /// Create a new, unconfigured logger.
-    pub fn new() -> Self {
-        let empty_log = Box::new(|_record: &log::Record| {});
-
-        Self {
-            max_level: LevelFilter::Trace,
-            filters: Vec::new(),
-
-            raw_fn: empty_log.clone(),
-            info_fn: empty_log.clone(),
-            warn_fn: empty_log.clone(),
-            error_fn: empty_log.clone(),
-            debug_fn: empty_log.clone(),
-            trace_fn: empty_log.clone(),
-        }
-    }
-
-    /// Create a new logger configured from the [`DEFAULT_ENV`] logging variable.
-    pub fn new_from_default_env() -> Self {
-        Self::new_with_env(DEFAULT_ENV)
-    }
-
-    /// Create a new logger configured with the environment variable given by `var_name`.
-    pub fn new_with_env<T: AsRef<OsStr>>(var_name: T) -> Self {
-        let mut youlog = Self::new();
-
-        match std::env::var(var_name) {
-            Ok(v) => {
-                // Discard the regex, if any
-                // The unwrap should be safe but supply a default anyways
-                let filters = v.split('/').next().unwrap_or_default();
-
-                for s in filters.split(',').map(|v| v.trim()) {
-                    if s.is_empty() {
-                        continue;
-                    }
-
-                    let mut parts = s.split('=');
-                    let (name, level) =
-                        match (parts.next(), parts.next().map(|p| p.trim()), parts.next()) {
-                            // level,
-                            // name,
-                            (Some(name), None, None) => match name.parse() {
-                                Ok(level) => (None, level),
-                                Err(_) => (Some(name), LevelFilter::max()),
-                            },
-                            // name=,
-                            (Some(name), Some(""), None) => (Some(name), LevelFilter::max()),
-                            // name=level
-                            (Some(name), Some(level), None) => match level.parse() {
-                                Ok(level) => (Some(name), level),
-                                Err(_) => {
-                                    eprintln!("warning: invalid logging spec '{level}', ignoring");
-                                    continue;
-                                }
-                            },
-                            _ => {
-                                eprintln!("warning: invalid logging spec '{s}', ignoring");
-                                continue;
-                            }
-                        };
-
-                    youlog = if let Some(name) = name {
-                        youlog.level(name, level)
-                    } else {
-                        youlog.global_level(level)
-                    };
-                }
-            }
-            Err(e) => eprintln!("{e}"),
-        }
-
-        youlog
-    }
-
-    /// Initialize and consume the logger.
-    pub fn init(mut self) -> Result<(), log::SetLoggerError> {
-        self.filters.sort_unstable_by(|a, b| {
-            let a_len = a.name.len();
-            let b_len = b.name.len();
-
-            a_len.cmp(&b_len)
-        });
-
-        log::set_max_level(self.max_level);
-        log::set_boxed_logger(Box::new(self))
-    }
-
-    /// Set the log level globally. Does not override module-specific levels.
-    pub fn global_level(mut self, level: LevelFilter) -> Self {
-        self.max_level = level;
-
-        self
-    }
-
-    /// Set the log level for a specific module. Overrides the global log level.
-    pub fn level(mut self, module: impl AsRef<str>, level: LevelFilter) -> Self {
-        let name = module.as_ref();
-
-        if self.filters.iter().any(|v| v.name == name) {
-            eprintln!("warning: level filter for '{name}' already exists, ignoring");
-            return self;
-        } else {
-            self.filters.push(Filter {
-                name: name.to_string(),
-                level,
-            });
-        }
-
-        self
-    }
-
-    /// Set a logging function for a given [`LevelFilter`].
-    pub fn log_fn(
-        mut self,
-        level: LevelFilter,
-        function: impl Fn(&log::Record) + Send + Sync + 'static,
-    ) -> Self {
-        let function = Box::new(function);
-        match level {
-            LevelFilter::Off => {
-                eprintln!("warning: setting a log fn for LevelFilter::Off doesn't do anything");
-            }
-            LevelFilter::Error => self.error_fn = function,
-            LevelFilter::Warn => self.warn_fn = function,
-            LevelFilter::Info => self.info_fn = function,
-            LevelFilter::Debug => self.debug_fn = function,
-            LevelFilter::Trace => self.trace_fn = function,
-        }
-
-        self
-    }
-
-    /// Set a logging function that is called across logging levels up to the global logging level.
-    ///
-    /// # NOTE
-    /// This logging function is called before other logging functions.
-    pub fn raw_fn(mut self, function: impl Fn(&log::Record) + Send + Sync + 'static) -> Self {
-        self.raw_fn = Box::new(function);
-
-        self
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use log::{debug, error, info, trace, Level, Log, Metadata, MetadataBuilder, Record};
-    use std::sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    };
-
-    /// Helper function for reading an [AtomicUsize].
-    fn count(counter: &Arc<AtomicUsize>) -> usize {
-        counter.load(Ordering::Relaxed)
-    }
-
-    /// Helper function for creating a pair of [Arc<AtomicUsize>] that both
-    /// point to the same [AtomicUsize].
-    fn create_counter() -> (Arc<AtomicUsize>, Arc<AtomicUsize>) {
-        let counter = Arc::new(AtomicUsize::new(0));
-        let clone = counter.clone();
-
-        (counter, clone)
-    }
-
-    fn create_metadata(target: &str, level: Level) -> Metadata {
-        MetadataBuilder::new().target(target).level(level).build()
-    }
-
-    // TODO individual tests must be run as separate test binaries, otherwise the logger fails to init across tests
-    #[test]
-    fn logging() {
-        let (info_counter, closure_info_counter) = create_counter();
-        let (debug_counter, closure_debug_counter) = create_counter();
-        // Intentionally not incremented
-        let (warn_counter, closure_warn_counter) = create_counter();
-        // Intentionally not incremented
-        let (trace_counter, closure_trace_counter) = create_counter();
-
-        static mut FN_INT: AtomicUsize = AtomicUsize::new(0);
-
-        fn raw_fn(_r: &Record) {
-            unsafe { FN_INT.fetch_add(1, Ordering::Relaxed) };
-        }
-
-        Youlog::new()
-            .global_level(LevelFilter::Debug)
-            .log_fn(LevelFilter::Info, move |r| {
-                closure_info_counter.fetch_add(1, Ordering::Relaxed);
-                println!("info {}", r.args().as_str().unwrap_or_default());
-            })
-            .log_fn(LevelFilter::Debug, move |r| {
-                closure_debug_counter.fetch_add(1, Ordering::Relaxed);
-                println!("debug {}", r.args().as_str().unwrap_or_default());
-            })
-            .log_fn(LevelFilter::Warn, move |_r| {
-                println!("warn {}", count(&closure_warn_counter));
-            })
-            .log_fn(LevelFilter::Trace, move |r| {
-                closure_trace_counter.fetch_add(1, Ordering::Relaxed);
-                println!("trace {}", r.args().as_str().unwrap_or_default());
-            })
-            .raw_fn(raw_fn)
-            .init()
-            .expect("failed to init Youlog");
-
-        assert_eq!(count(&info_counter), 0);
-        assert_eq!(count(&debug_counter), 0);
-        assert_eq!(count(&warn_counter), 0);
-        assert_eq!(count(&trace_counter), 0);
-        unsafe { assert_eq!(FN_INT.load(Ordering::Relaxed), 0) };
-
-        info!("blah");
-        trace!("failed trace");
-        // Just making sure :)
-        error!("failed error");
-
-        assert_eq!(count(&info_counter), 1);
-        assert_eq!(count(&debug_counter), 0);
-        assert_eq!(count(&warn_counter), 0);
-        assert_eq!(count(&trace_counter), 0);
-        unsafe { assert_eq!(FN_INT.load(Ordering::Relaxed), 2) };
-
-        info!("bleh");
-        debug!("wee");
-        trace!("failed trace");
-
-        assert_eq!(count(&info_counter), 2);
-        assert_eq!(count(&debug_counter), 1);
-        assert_eq!(count(&warn_counter), 0);
-        assert_eq!(count(&trace_counter), 0);
-        unsafe { assert_eq!(FN_INT.load(Ordering::Relaxed), 4) };
-    }
-
-    #[test]
-    fn filter_enabled() {
-        let mut youlog = Youlog::new().level("test", LevelFilter::Info);
-
-        assert!(youlog.enabled(&create_metadata("test", Level::Info)));
-        assert!(youlog.enabled(&create_metadata("test::blah", Level::Info)));
-        assert!(youlog.enabled(&create_metadata("test::blah::eh", Level::Info)));
-        assert!(!youlog.enabled(&create_metadata("other", Level::Info)));
-        assert!(!youlog.enabled(&create_metadata("test", Level::Trace)));
-
-        assert!(!youlog.enabled(&create_metadata("test::blah", Level::Debug)));
-
-        youlog = youlog.level("test::blah", LevelFilter::Debug);
-
-        assert!(youlog.enabled(&create_metadata("test::blah", Level::Debug)));
-    }
-
-    #[test]
-    fn env() {
-        std::env::set_var(DEFAULT_ENV, "debug,test=info,other=debug,bleh=error");
-        let youlog = Youlog::new_from_default_env();
-
-        assert!(youlog.enabled(&create_metadata("test", Level::Info)));
-        assert!(youlog.enabled(&create_metadata("other", Level::Debug)));
-        assert_eq!(youlog.max_level, LevelFilter::Debug);
-
-        std::env::set_var("SPECIAL_RUST_LOG", "error,test=error");
-        let youlog = Youlog::new_with_env("SPECIAL_RUST_LOG");
-
-        assert!(youlog.enabled(&create_metadata("test", Level::Error)));
-        assert_eq!(youlog.max_level, LevelFilter::Error);
-    }
-}
+/*!
+# youlog
+
+A thin logging implementation for Rust's [log](https://github.com/rust-lang/log) facade.
+
+This crate allows for providing custom functions to the logger.
+
+Examples where this might be useful:
+
+- Logging logic needs to be different across log levels
+- Another application's logger is being used like with [godot-rust](https://github.com/godot-rust)
+- An existing crate is too opinionated in how it handles logging
+
+## Features
+
+- Setting logging functions per log level
+- Setting a logging function across all log levels
+- Filtering logs per module/filter
+- Initializing filters from an environment variable (`RUST_LOG` by default)
+- Consuming structured key-values attached to a record via [`log`]'s `kv` feature
+- Filtering logs by the value of a structured key-value pair
+- Filtering logs by the rendered message body, via the `RUST_LOG=spec/pattern` convention
+- Routing specific targets to their own function, bypassing the per-level functions
+- Buffering records onto a background thread, with `flush` blocking until they're drained
+- Respecting `log::STATIC_MAX_LEVEL`, with an `enabled_for` guard to pre-check a record cheaply
+
+## Example
+
+```
+use log::LevelFilter;
+use youlog::Youlog;
+
+Youlog::new()
+    .global_level(LevelFilter::Info)
+    .log_fn(LevelFilter::Info, |record| {
+        println!("info {}", record.args().as_str().unwrap_or_default());
+    })
+    .raw_fn(|record| {
+        println!("raw {}", record.args().as_str().unwrap_or_default());
+    })
+    .level("some_module", LevelFilter::Error)
+    .init()
+    .expect("unable to init logger");
+
+log::info!("this is an info log!");
+```
+
+# License
+
+MPL-2.0
+
+Filter implementation referenced from [`env_logger`](https://github.com/rust-cli/env_logger).
+*/
+
+use log::LevelFilter;
+use std::{
+    ffi::OsStr,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+};
+
+type LogFn = Box<dyn Fn(&log::Record) + Sync + Send>;
+type KvFn = Box<dyn Fn(&log::Record, &[(String, String)]) + Sync + Send>;
+type KvPredicate = Box<dyn Fn(&str) -> bool + Sync + Send>;
+
+/// The default environment variable containing logging filters.
+pub const DEFAULT_ENV: &str = "RUST_LOG";
+
+/// A filter for a module.
+#[derive(Clone)]
+struct Filter {
+    /// The name of the module to filter.
+    name: String,
+    /// The max logging level for the module.
+    level: LevelFilter,
+}
+
+/// A filter on a single structured key-value pair, as exposed by
+/// [`log::Record::key_values`].
+struct KvFilter {
+    /// The key to look up in a record's key-values.
+    key: String,
+    /// The predicate the value (rendered as a string) must satisfy.
+    predicate: KvPredicate,
+}
+
+/// Walks a [`log::kv::Source`], collecting every pair into owned strings.
+struct KvCollector {
+    pairs: Vec<(String, String)>,
+}
+
+impl<'kvs> log::kv::VisitSource<'kvs> for KvCollector {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.pairs.push((key.as_str().to_string(), value.to_string()));
+
+        Ok(())
+    }
+}
+
+/// Collects a record's structured key-values into a `Vec` of owned strings.
+fn collect_key_values(record: &log::Record) -> Vec<(String, String)> {
+    let mut collector = KvCollector { pairs: Vec::new() };
+    let _ = record.key_values().visit(&mut collector);
+
+    collector.pairs
+}
+
+/// A compiled message filter, matched against a record's rendered `args()`.
+///
+/// With the `regex` feature enabled this is a real regular expression, as the `RUST_LOG`
+/// grammar expects. Without it, falls back to plain substring containment so the crate
+/// doesn't force a regex dependency on everyone.
+enum MessageFilter {
+    #[cfg(feature = "regex")]
+    Regex(regex::Regex),
+    #[cfg(not(feature = "regex"))]
+    Substring(String),
+}
+
+impl MessageFilter {
+    /// Compile `pattern` into a [`MessageFilter`], printing a `warning:` and returning `None`
+    /// if it's an invalid regex (only possible with the `regex` feature enabled).
+    fn new(pattern: &str) -> Option<Self> {
+        #[cfg(feature = "regex")]
+        {
+            match regex::Regex::new(pattern) {
+                Ok(regex) => Some(Self::Regex(regex)),
+                Err(_) => {
+                    eprintln!("warning: invalid message filter regex '{pattern}', ignoring");
+                    None
+                }
+            }
+        }
+
+        #[cfg(not(feature = "regex"))]
+        {
+            Some(Self::Substring(pattern.to_string()))
+        }
+    }
+
+    /// Whether the rendered message body matches this filter.
+    fn is_match(&self, message: &str) -> bool {
+        match self {
+            #[cfg(feature = "regex")]
+            Self::Regex(regex) => regex.is_match(message),
+            #[cfg(not(feature = "regex"))]
+            Self::Substring(substring) => message.contains(substring.as_str()),
+        }
+    }
+}
+
+/// Whether `target` is matched by the prefix `name`.
+///
+/// `log` targets are `::`-separated module paths, so a prefix only matches on a component
+/// boundary: `name` must equal `target` outright, or `target` must continue right after
+/// `name` with `::`. This keeps `"net"` from matching `"network"`. A trailing `::` on `name`
+/// is stripped first, so `"net::"` behaves the same as `"net"`.
+fn target_matches(name: &str, target: &str) -> bool {
+    let name = name.strip_suffix("::").unwrap_or(name);
+
+    target == name
+        || target
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with("::"))
+}
+
+/// A function registered against a target prefix, alongside the prefix it matches.
+struct TargetFn {
+    /// The target prefix to match.
+    name: String,
+    /// The function to invoke for a matching record, instead of the per-level function.
+    function: LogFn,
+}
+
+/// Finds the longest-matching name in `candidates` (sorted shortest-to-longest) for `target`,
+/// if any.
+fn find_longest_match<'a, T>(
+    candidates: &'a [T],
+    target: &str,
+    name_of: impl Fn(&T) -> &str,
+) -> Option<&'a T> {
+    candidates
+        .iter()
+        .rev()
+        .find(|candidate| target_matches(name_of(candidate), target))
+}
+
+/// The index `target_fns` should insert `name` at to keep the list sorted shortest-to-longest.
+fn sorted_insert_index(target_fns: &[TargetFn], name: &str) -> usize {
+    target_fns.partition_point(|existing| existing.name.len() <= name.len())
+}
+
+/// Everything needed to actually process a record: the per-record filters and the user
+/// functions. Held behind an `Arc` so [`Youlog::buffered`] can hand a clone to the background
+/// dispatch thread while the foreground logger keeps its own.
+struct Dispatcher {
+    kv_filters: Vec<KvFilter>,
+    message_filter: Option<MessageFilter>,
+    target_fns: Vec<TargetFn>,
+
+    raw_fn: LogFn,
+    info_fn: LogFn,
+    warn_fn: LogFn,
+    error_fn: LogFn,
+    debug_fn: LogFn,
+    trace_fn: LogFn,
+    kv_fn: Option<KvFn>,
+}
+
+impl Dispatcher {
+    fn new() -> Self {
+        let empty_log = Box::new(|_record: &log::Record| {});
+
+        Self {
+            kv_filters: Vec::new(),
+            message_filter: None,
+            target_fns: Vec::new(),
+
+            raw_fn: empty_log.clone(),
+            info_fn: empty_log.clone(),
+            warn_fn: empty_log.clone(),
+            error_fn: empty_log.clone(),
+            debug_fn: empty_log.clone(),
+            trace_fn: empty_log.clone(),
+            kv_fn: None,
+        }
+    }
+
+    /// Run every configured filter and function against `record`.
+    fn dispatch(&self, record: &log::Record) {
+        if let Some(message_filter) = &self.message_filter {
+            let message = record.args().to_string();
+            if !message_filter.is_match(&message) {
+                return;
+            }
+        }
+
+        let key_values = if self.kv_fn.is_some() || !self.kv_filters.is_empty() {
+            Some(collect_key_values(record))
+        } else {
+            None
+        };
+
+        if let Some(key_values) = &key_values {
+            for kv_filter in self.kv_filters.iter() {
+                let matches = key_values
+                    .iter()
+                    .find(|(key, _)| key == &kv_filter.key)
+                    .is_some_and(|(_, value)| (kv_filter.predicate)(value));
+
+                if !matches {
+                    return;
+                }
+            }
+        }
+
+        (self.raw_fn)(record);
+        if let Some(kv_fn) = &self.kv_fn {
+            kv_fn(record, key_values.as_deref().unwrap_or_default());
+        }
+
+        if let Some(target_fn) = find_longest_match(&self.target_fns, record.target(), |t| {
+            t.name.as_str()
+        }) {
+            (target_fn.function)(record);
+            return;
+        }
+
+        match record.level() {
+            log::Level::Error => (self.error_fn)(record),
+            log::Level::Warn => (self.warn_fn)(record),
+            log::Level::Info => (self.info_fn)(record),
+            log::Level::Debug => (self.debug_fn)(record),
+            log::Level::Trace => (self.trace_fn)(record),
+        }
+    }
+}
+
+/// A [`log::Record`] decoupled from the borrows it normally carries, so it can be sent to a
+/// background thread.
+struct OwnedRecord {
+    level: log::Level,
+    target: String,
+    args: String,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    key_values: Vec<(String, String)>,
+}
+
+impl OwnedRecord {
+    fn from_record(record: &log::Record, key_values: Vec<(String, String)>) -> Self {
+        Self {
+            level: record.level(),
+            target: record.target().to_string(),
+            args: record.args().to_string(),
+            module_path: record.module_path().map(str::to_string),
+            file: record.file().map(str::to_string),
+            line: record.line(),
+            key_values,
+        }
+    }
+
+    /// Reconstruct a borrowed [`log::Record`] from this owned data and pass it to `f`. Takes
+    /// a callback rather than returning the `Record` because it borrows from locals (the
+    /// rendered `args` and the `&str` key-value pairs) that don't outlive this call.
+    fn with_record<R>(&self, f: impl FnOnce(&log::Record) -> R) -> R {
+        let key_values: Vec<(&str, &str)> = self
+            .key_values
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        let args = format_args!("{}", self.args);
+
+        let record = log::Record::builder()
+            .level(self.level)
+            .target(&self.target)
+            .args(args)
+            .module_path(self.module_path.as_deref())
+            .file(self.file.as_deref())
+            .line(self.line)
+            .key_values(&key_values)
+            .build();
+
+        f(&record)
+    }
+}
+
+/// A bounded channel and the background thread draining it, backing [`Youlog::buffered`].
+/// `flush` blocks on `pending` until the worker has caught up.
+struct Buffer {
+    sender: mpsc::SyncSender<OwnedRecord>,
+    pending: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Buffer {
+    fn spawn(capacity: usize, dispatcher: Arc<Dispatcher>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<OwnedRecord>(capacity);
+        let pending = Arc::new((Mutex::new(0usize), Condvar::new()));
+        let worker_pending = Arc::clone(&pending);
+
+        thread::spawn(move || {
+            for owned in receiver {
+                owned.with_record(|record| dispatcher.dispatch(record));
+
+                let (lock, condvar) = &*worker_pending;
+                let mut count = lock.lock().unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    condvar.notify_all();
+                }
+            }
+        });
+
+        Self { sender, pending }
+    }
+
+    fn push(&self, record: OwnedRecord) {
+        {
+            let (lock, _) = &*self.pending;
+            *lock.lock().unwrap() += 1;
+        }
+
+        // If the worker thread is gone there's nothing left to flush to; undo the increment
+        // above rather than leave `flush` waiting forever on a record nobody will process.
+        if self.sender.send(record).is_err() {
+            let (lock, condvar) = &*self.pending;
+            let mut count = lock.lock().unwrap();
+            *count -= 1;
+            if *count == 0 {
+                condvar.notify_all();
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let (lock, condvar) = &*self.pending;
+        let _guard = condvar
+            .wait_while(lock.lock().unwrap(), |count| *count > 0)
+            .unwrap();
+    }
+}
+
+/// A logger that accepts user functions. Filters are optional, and by default all logs
+/// are enabled. This allows the user functions to implement their own per-level filters.
+pub struct Youlog {
+    max_level: LevelFilter,
+    filters: Vec<Filter>,
+    dispatcher: Arc<Dispatcher>,
+    buffer: Option<Buffer>,
+}
+
+impl log::Log for Youlog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        if metadata.level() > log::STATIC_MAX_LEVEL {
+            return false;
+        }
+
+        match find_longest_match(&self.filters, metadata.target(), |f| f.name.as_str()) {
+            Some(filter) => metadata.level() <= filter.level,
+            // No per-module filter matches this target, so fall back to the global level.
+            None => metadata.level() <= self.max_level,
+        }
+    }
+
+    fn log(&self, record: &log::Record) {
+        match &self.buffer {
+            Some(buffer) => {
+                let key_values = collect_key_values(record);
+                buffer.push(OwnedRecord::from_record(record, key_values));
+            }
+            None => self.dispatcher.dispatch(record),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(buffer) = &self.buffer {
+            buffer.flush();
+        }
+    }
+}
+
+impl Default for Youlog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Youlog {
+    /// Create a new, unconfigured logger.
+    pub fn new() -> Self {
+        Self {
+            max_level: LevelFilter::Trace,
+            filters: Vec::new(),
+            dispatcher: Arc::new(Dispatcher::new()),
+            buffer: None,
+        }
+    }
+
+    /// Create a new logger configured from the [`DEFAULT_ENV`] logging variable.
+    pub fn new_from_default_env() -> Self {
+        Self::new_with_env(DEFAULT_ENV)
+    }
+
+    /// Create a new logger configured with the environment variable given by `var_name`.
+    pub fn new_with_env<T: AsRef<OsStr>>(var_name: T) -> Self {
+        let mut youlog = Self::new();
+
+        match std::env::var(var_name) {
+            Ok(v) => {
+                // Everything after the first `/` is a message filter, matched against the
+                // rendered body of each record. The unwraps should be safe but supply
+                // defaults anyways.
+                let mut split = v.splitn(2, '/');
+                let filters = split.next().unwrap_or_default();
+                let pattern = split.next().unwrap_or_default();
+
+                if !pattern.is_empty() {
+                    youlog.dispatcher_mut().message_filter = MessageFilter::new(pattern);
+                }
+
+                for s in filters.split(',').map(|v| v.trim()) {
+                    if s.is_empty() {
+                        continue;
+                    }
+
+                    let mut parts = s.split('=');
+                    let (name, level) =
+                        match (parts.next(), parts.next().map(|p| p.trim()), parts.next()) {
+                            // level,
+                            // name,
+                            (Some(name), None, None) => match name.parse() {
+                                Ok(level) => (None, level),
+                                Err(_) => (Some(name), LevelFilter::max()),
+                            },
+                            // name=,
+                            (Some(name), Some(""), None) => (Some(name), LevelFilter::max()),
+                            // name=level
+                            (Some(name), Some(level), None) => match level.parse() {
+                                Ok(level) => (Some(name), level),
+                                Err(_) => {
+                                    eprintln!("warning: invalid logging spec '{level}', ignoring");
+                                    continue;
+                                }
+                            },
+                            _ => {
+                                eprintln!("warning: invalid logging spec '{s}', ignoring");
+                                continue;
+                            }
+                        };
+
+                    youlog = if let Some(name) = name {
+                        youlog.level(name, level)
+                    } else {
+                        youlog.global_level(level)
+                    };
+                }
+            }
+            Err(e) => eprintln!("{e}"),
+        }
+
+        youlog
+    }
+
+    /// Initialize and consume the logger.
+    pub fn init(mut self) -> Result<(), log::SetLoggerError> {
+        self.filters.sort_unstable_by(|a, b| {
+            let a_len = a.name.len();
+            let b_len = b.name.len();
+
+            a_len.cmp(&b_len)
+        });
+
+        // `log::STATIC_MAX_LEVEL` reflects the `max_level_*`/`release_max_level_*` cargo
+        // features: anything above it was stripped at compile time, so there's no point
+        // asking the runtime to emit it.
+        log::set_max_level(self.max_level.min(log::STATIC_MAX_LEVEL));
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    /// Mutable access to the [`Dispatcher`], which is unique to this `Youlog` until
+    /// [`Youlog::buffered`] hands a clone to its background thread.
+    fn dispatcher_mut(&mut self) -> &mut Dispatcher {
+        Arc::get_mut(&mut self.dispatcher)
+            .expect("Youlog: functions and filters must be configured before `buffered`")
+    }
+
+    /// Cheaply check whether a record for `target` at `level` would be emitted, combining
+    /// `log::STATIC_MAX_LEVEL`, the global level, and the longest-matching module filter.
+    /// Useful for guarding expensive work before it's formatted, the same way
+    /// `log::log_enabled!` guards a whole `log::log!` call.
+    pub fn enabled_for(&self, target: impl AsRef<str>, level: log::Level) -> bool {
+        let metadata = log::Metadata::builder()
+            .target(target.as_ref())
+            .level(level)
+            .build();
+
+        log::Log::enabled(self, &metadata)
+    }
+
+    /// Set the log level globally. Does not override module-specific levels.
+    pub fn global_level(mut self, level: LevelFilter) -> Self {
+        self.max_level = level;
+
+        self
+    }
+
+    /// Set the log level for a specific module. Overrides the global log level.
+    pub fn level(mut self, module: impl AsRef<str>, level: LevelFilter) -> Self {
+        let name = module.as_ref();
+
+        if self.filters.iter().any(|v| v.name == name) {
+            eprintln!("warning: level filter for '{name}' already exists, ignoring");
+            return self;
+        } else {
+            // Keep `filters` sorted shortest-to-longest so `enabled`/`enabled_for` find the
+            // longest match even before `init` runs.
+            let index = self
+                .filters
+                .partition_point(|existing| existing.name.len() <= name.len());
+            self.filters.insert(
+                index,
+                Filter {
+                    name: name.to_string(),
+                    level,
+                },
+            );
+        }
+
+        self
+    }
+
+    /// Register a function for records whose target matches `target_prefix`, routing them
+    /// there *instead of* the per-level function. If multiple registered prefixes match a
+    /// record's target, the longest one wins (the same rule used for module filters).
+    pub fn target_fn(
+        mut self,
+        target_prefix: impl AsRef<str>,
+        function: impl Fn(&log::Record) + Send + Sync + 'static,
+    ) -> Self {
+        let name = target_prefix.as_ref();
+        let dispatcher = self.dispatcher_mut();
+
+        if dispatcher.target_fns.iter().any(|v| v.name == name) {
+            eprintln!("warning: target fn for '{name}' already exists, ignoring");
+            return self;
+        }
+
+        let index = sorted_insert_index(&dispatcher.target_fns, name);
+        dispatcher.target_fns.insert(
+            index,
+            TargetFn {
+                name: name.to_string(),
+                function: Box::new(function),
+            },
+        );
+
+        self
+    }
+
+    /// Set a logging function for a given [`LevelFilter`].
+    pub fn log_fn(
+        mut self,
+        level: LevelFilter,
+        function: impl Fn(&log::Record) + Send + Sync + 'static,
+    ) -> Self {
+        let function = Box::new(function);
+        let dispatcher = self.dispatcher_mut();
+        match level {
+            LevelFilter::Off => {
+                eprintln!("warning: setting a log fn for LevelFilter::Off doesn't do anything");
+            }
+            LevelFilter::Error => dispatcher.error_fn = function,
+            LevelFilter::Warn => dispatcher.warn_fn = function,
+            LevelFilter::Info => dispatcher.info_fn = function,
+            LevelFilter::Debug => dispatcher.debug_fn = function,
+            LevelFilter::Trace => dispatcher.trace_fn = function,
+        }
+
+        self
+    }
+
+    /// Set a logging function that is called across logging levels up to the global logging level.
+    ///
+    /// # NOTE
+    /// This logging function is called before other logging functions.
+    pub fn raw_fn(mut self, function: impl Fn(&log::Record) + Send + Sync + 'static) -> Self {
+        self.dispatcher_mut().raw_fn = Box::new(function);
+
+        self
+    }
+
+    /// Set a function that is called with a record's structured key-values, collected from
+    /// [`log::Record::key_values`] into a `Vec<(String, String)>`.
+    ///
+    /// # NOTE
+    /// This function is called after [`Youlog::raw_fn`] but before the per-level functions.
+    pub fn kv_fn(
+        mut self,
+        function: impl Fn(&log::Record, &[(String, String)]) + Send + Sync + 'static,
+    ) -> Self {
+        self.dispatcher_mut().kv_fn = Some(Box::new(function));
+
+        self
+    }
+
+    /// Drop records whose structured key-values don't contain `key`, or whose value for `key`
+    /// doesn't satisfy `predicate`. Multiple calls stack; a record must satisfy all of them.
+    pub fn filter_kv(
+        mut self,
+        key: impl AsRef<str>,
+        predicate: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.dispatcher_mut().kv_filters.push(KvFilter {
+            key: key.as_ref().to_string(),
+            predicate: Box::new(predicate),
+        });
+
+        self
+    }
+
+    /// Spin up a background thread and dispatch records through a bounded channel of
+    /// `capacity` instead of inline on the calling thread. [`log::Log::flush`] then blocks
+    /// until the channel is drained.
+    ///
+    /// # NOTE
+    /// Call this after every other configuration method; functions and filters can no
+    /// longer be changed once the background thread owns them.
+    pub fn buffered(mut self, capacity: usize) -> Self {
+        self.buffer = Some(Buffer::spawn(capacity, Arc::clone(&self.dispatcher)));
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{debug, error, info, trace, Level, Log, Metadata, MetadataBuilder, Record};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Helper function for reading an [AtomicUsize].
+    fn count(counter: &Arc<AtomicUsize>) -> usize {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Helper function for creating a pair of [Arc<AtomicUsize>] that both
+    /// point to the same [AtomicUsize].
+    fn create_counter() -> (Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let clone = counter.clone();
+
+        (counter, clone)
+    }
+
+    fn create_metadata(target: &str, level: Level) -> Metadata<'_> {
+        MetadataBuilder::new().target(target).level(level).build()
+    }
+
+    // TODO individual tests must be run as separate test binaries, otherwise the logger fails to init across tests
+    #[test]
+    fn logging() {
+        let (info_counter, closure_info_counter) = create_counter();
+        let (debug_counter, closure_debug_counter) = create_counter();
+        // Intentionally not incremented
+        let (warn_counter, closure_warn_counter) = create_counter();
+        // Intentionally not incremented
+        let (trace_counter, closure_trace_counter) = create_counter();
+
+        static FN_INT: AtomicUsize = AtomicUsize::new(0);
+
+        fn raw_fn(_r: &Record) {
+            FN_INT.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Youlog::new()
+            .global_level(LevelFilter::Debug)
+            .log_fn(LevelFilter::Info, move |r| {
+                closure_info_counter.fetch_add(1, Ordering::Relaxed);
+                println!("info {}", r.args().as_str().unwrap_or_default());
+            })
+            .log_fn(LevelFilter::Debug, move |r| {
+                closure_debug_counter.fetch_add(1, Ordering::Relaxed);
+                println!("debug {}", r.args().as_str().unwrap_or_default());
+            })
+            .log_fn(LevelFilter::Warn, move |_r| {
+                println!("warn {}", count(&closure_warn_counter));
+            })
+            .log_fn(LevelFilter::Trace, move |r| {
+                closure_trace_counter.fetch_add(1, Ordering::Relaxed);
+                println!("trace {}", r.args().as_str().unwrap_or_default());
+            })
+            .raw_fn(raw_fn)
+            .init()
+            .expect("failed to init Youlog");
+
+        assert_eq!(count(&info_counter), 0);
+        assert_eq!(count(&debug_counter), 0);
+        assert_eq!(count(&warn_counter), 0);
+        assert_eq!(count(&trace_counter), 0);
+        assert_eq!(FN_INT.load(Ordering::Relaxed), 0);
+
+        info!("blah");
+        trace!("failed trace");
+        // Just making sure :)
+        error!("failed error");
+
+        assert_eq!(count(&info_counter), 1);
+        assert_eq!(count(&debug_counter), 0);
+        assert_eq!(count(&warn_counter), 0);
+        assert_eq!(count(&trace_counter), 0);
+        assert_eq!(FN_INT.load(Ordering::Relaxed), 2);
+
+        info!("bleh");
+        debug!("wee");
+        trace!("failed trace");
+
+        assert_eq!(count(&info_counter), 2);
+        assert_eq!(count(&debug_counter), 1);
+        assert_eq!(count(&warn_counter), 0);
+        assert_eq!(count(&trace_counter), 0);
+        assert_eq!(FN_INT.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn filter_enabled() {
+        // `global_level(Off)` keeps the no-filter fallback below from masking the
+        // filter-specific assertions.
+        let mut youlog = Youlog::new()
+            .global_level(LevelFilter::Off)
+            .level("test", LevelFilter::Info);
+
+        assert!(youlog.enabled(&create_metadata("test", Level::Info)));
+        assert!(youlog.enabled(&create_metadata("test::blah", Level::Info)));
+        assert!(youlog.enabled(&create_metadata("test::blah::eh", Level::Info)));
+        // No filter matches "other", so it falls back to the (disabled) global level.
+        assert!(!youlog.enabled(&create_metadata("other", Level::Info)));
+        assert!(!youlog.enabled(&create_metadata("test", Level::Trace)));
+
+        assert!(!youlog.enabled(&create_metadata("test::blah", Level::Debug)));
+
+        youlog = youlog.level("test::blah", LevelFilter::Debug);
+
+        assert!(youlog.enabled(&create_metadata("test::blah", Level::Debug)));
+    }
+
+    #[test]
+    fn filter_enabled_fallback() {
+        // With no per-module filters at all, `enabled` consults the global level directly.
+        let youlog = Youlog::new().global_level(LevelFilter::Warn);
+
+        assert!(youlog.enabled(&create_metadata("anything", Level::Warn)));
+        assert!(!youlog.enabled(&create_metadata("anything", Level::Info)));
+    }
+
+    #[test]
+    fn filter_target_boundary() {
+        let youlog = Youlog::new()
+            .global_level(LevelFilter::Off)
+            .level("net", LevelFilter::Info);
+
+        assert!(youlog.enabled(&create_metadata("net", Level::Info)));
+        assert!(youlog.enabled(&create_metadata("net::socket", Level::Info)));
+        // "network" starts with "net" but isn't a `::`-bounded continuation of it, so it
+        // falls back to the (disabled) global level instead of matching the filter.
+        assert!(!youlog.enabled(&create_metadata("network", Level::Info)));
+
+        let youlog = Youlog::new()
+            .global_level(LevelFilter::Off)
+            .level("test", LevelFilter::Info);
+
+        assert!(youlog.enabled(&create_metadata("test::blah", Level::Info)));
+        assert!(!youlog.enabled(&create_metadata("testing", Level::Info)));
+    }
+
+    #[test]
+    fn enabled_for() {
+        let youlog = Youlog::new()
+            .global_level(LevelFilter::Off)
+            .level("test", LevelFilter::Info);
+
+        assert!(youlog.enabled_for("test", Level::Info));
+        assert!(youlog.enabled_for("test::blah", Level::Info));
+        assert!(!youlog.enabled_for("test", Level::Debug));
+        // No filter matches "other", so it falls back to the (disabled) global level.
+        assert!(!youlog.enabled_for("other", Level::Info));
+
+        // Without any matching filter, `enabled_for` consults the global level directly.
+        let permissive = Youlog::new().global_level(LevelFilter::Info);
+        assert!(permissive.enabled_for("anything", Level::Info));
+        assert!(!permissive.enabled_for("anything", Level::Debug));
+
+        // `log::STATIC_MAX_LEVEL` defaults to `Trace` unless this crate is built with one of
+        // the `max_level_*`/`release_max_level_*` features, so this only exercises the part
+        // of `enabled_for` that's testable without those features enabled.
+        assert!(Level::Trace <= log::STATIC_MAX_LEVEL);
+    }
+
+    #[test]
+    fn env() {
+        std::env::set_var(DEFAULT_ENV, "debug,test=info,other=debug,bleh=error");
+        let youlog = Youlog::new_from_default_env();
+
+        assert!(youlog.enabled(&create_metadata("test", Level::Info)));
+        assert!(youlog.enabled(&create_metadata("other", Level::Debug)));
+        assert_eq!(youlog.max_level, LevelFilter::Debug);
+
+        std::env::set_var("SPECIAL_RUST_LOG", "error,test=error");
+        let youlog = Youlog::new_with_env("SPECIAL_RUST_LOG");
+
+        assert!(youlog.enabled(&create_metadata("test", Level::Error)));
+        assert_eq!(youlog.max_level, LevelFilter::Error);
+    }
+
+    #[test]
+    fn env_message_filter() {
+        std::env::set_var("MESSAGE_RUST_LOG", "info/wanted");
+        let youlog = Youlog::new_with_env("MESSAGE_RUST_LOG");
+
+        let message_filter = youlog
+            .dispatcher
+            .message_filter
+            .as_ref()
+            .expect("message filter set");
+        assert!(message_filter.is_match("this message is wanted"));
+        assert!(!message_filter.is_match("this message is not"));
+    }
+
+    fn create_record<'a, const N: usize>(kvs: &'a [(&'a str, &'a str); N]) -> Record<'a> {
+        create_record_with_message(kvs, format_args!("test message"))
+    }
+
+    // `kvs` is a reference to a fixed-size array rather than a slice so it can be passed
+    // straight to `key_values` (arrays implement `log::kv::Source` and so coerce directly to
+    // `&dyn Source`; slices don't, since that would require unsizing an already-unsized type).
+    //
+    // `args` is built by the caller via `format_args!` rather than assembled from a `&str`
+    // here: `format_args!` captures references into anonymous temporaries scoped to the
+    // statement that invokes it, so building it from a parameter inside this function would
+    // tie the returned `Record` to data that doesn't outlive the call.
+    fn create_record_with_message<'a, const N: usize>(
+        kvs: &'a [(&'a str, &'a str); N],
+        args: std::fmt::Arguments<'a>,
+    ) -> Record<'a> {
+        Record::builder()
+            .args(args)
+            .level(Level::Info)
+            .target("test")
+            .key_values(kvs)
+            .build()
+    }
+
+    #[test]
+    fn kv() {
+        let (kv_counter, closure_kv_counter) = create_counter();
+
+        let youlog = Youlog::new().kv_fn(move |_r, kvs| {
+            closure_kv_counter.fetch_add(1, Ordering::Relaxed);
+            assert_eq!(kvs, &[("request_id".to_string(), "abc".to_string())]);
+        });
+
+        youlog.log(&create_record(&[("request_id", "abc")]));
+        assert_eq!(count(&kv_counter), 1);
+    }
+
+    #[test]
+    fn filter_kv() {
+        let (info_counter, closure_info_counter) = create_counter();
+
+        let youlog = Youlog::new()
+            .log_fn(LevelFilter::Info, move |_r| {
+                closure_info_counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .filter_kv("request_id", |v| v == "abc");
+
+        youlog.log(&create_record(&[("request_id", "abc")]));
+        assert_eq!(count(&info_counter), 1);
+
+        // Wrong value for `request_id`, dropped.
+        youlog.log(&create_record(&[("request_id", "xyz")]));
+        assert_eq!(count(&info_counter), 1);
+
+        // Missing `request_id` entirely, dropped.
+        youlog.log(&create_record(&[("other", "1")]));
+        assert_eq!(count(&info_counter), 1);
+    }
+
+    #[test]
+    fn message_filter() {
+        let (info_counter, closure_info_counter) = create_counter();
+
+        let mut youlog = Youlog::new().log_fn(LevelFilter::Info, move |_r| {
+            closure_info_counter.fetch_add(1, Ordering::Relaxed);
+        });
+        youlog.dispatcher_mut().message_filter = MessageFilter::new("wanted");
+
+        youlog.log(&create_record_with_message(&[], format_args!("this message is wanted")));
+        assert_eq!(count(&info_counter), 1);
+
+        youlog.log(&create_record_with_message(&[], format_args!("this message is not")));
+        assert_eq!(count(&info_counter), 1);
+    }
+
+    #[test]
+    fn target_fn() {
+        let (info_counter, closure_info_counter) = create_counter();
+        let (net_counter, closure_net_counter) = create_counter();
+        let (net_socket_counter, closure_net_socket_counter) = create_counter();
+
+        let youlog = Youlog::new()
+            .log_fn(LevelFilter::Info, move |_r| {
+                closure_info_counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .target_fn("net", move |_r| {
+                closure_net_counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .target_fn("net::socket", move |_r| {
+                closure_net_socket_counter.fetch_add(1, Ordering::Relaxed);
+            });
+
+        youlog.log(&create_record_with_message(&[], format_args!("unrouted")));
+        assert_eq!(count(&info_counter), 1);
+        assert_eq!(count(&net_counter), 0);
+
+        youlog.log(&Record::builder().target("net::dns").build());
+        assert_eq!(count(&info_counter), 1);
+        assert_eq!(count(&net_counter), 1);
+
+        // The longer, more specific prefix wins over the shorter one.
+        youlog.log(&Record::builder().target("net::socket").build());
+        assert_eq!(count(&net_counter), 1);
+        assert_eq!(count(&net_socket_counter), 1);
+    }
+
+    #[test]
+    fn buffered_flush() {
+        let (info_counter, closure_info_counter) = create_counter();
+
+        let youlog = Youlog::new()
+            .log_fn(LevelFilter::Info, move |_r| {
+                // Give the worker thread a chance to race `flush`, if it were going to.
+                thread::sleep(Duration::from_millis(10));
+                closure_info_counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .buffered(8);
+
+        for _ in 0..4 {
+            youlog.log(&create_record_with_message(&[], format_args!("buffered")));
+        }
+        youlog.flush();
+
+        assert_eq!(count(&info_counter), 4);
+    }
+}